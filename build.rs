@@ -0,0 +1,37 @@
+use std::{env, fs, path::PathBuf};
+
+/// Exposes the locked version of the libsql crate as `LIBSQL_VERSION`, for `libsql.version()`.
+fn main() {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").expect("manifest dir"));
+    let lockfile = manifest_dir
+        .ancestors()
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|path| path.exists());
+
+    let version = lockfile
+        .as_ref()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|lock| locked_version(&lock, "libsql"))
+        .unwrap_or_else(|| "unknown".to_owned());
+
+    if let Some(path) = lockfile {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rustc-env=LIBSQL_VERSION={version}");
+}
+
+fn locked_version(lock: &str, name: &str) -> Option<String> {
+    let name_line = format!("name = \"{name}\"");
+    lock.split("[[package]]").find_map(|package| {
+        let mut lines = package.lines().map(str::trim);
+        if !lines.any(|line| line == name_line) {
+            return None;
+        }
+        package.lines().map(str::trim).find_map(|line| {
+            line.strip_prefix("version = \"")
+                .and_then(|version| version.strip_suffix('"'))
+                .map(ToOwned::to_owned)
+        })
+    })
+}
@@ -1 +1 @@
-require("libsql.core")
+return require("libsql.core")